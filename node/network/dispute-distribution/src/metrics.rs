@@ -0,0 +1,130 @@
+// Copyright 2021 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+use polkadot_node_metrics::metrics::{self, prometheus};
+
+/// Dispute distribution metrics.
+#[derive(Clone, Default)]
+pub struct Metrics(Option<MetricsInner>);
+
+#[derive(Clone)]
+struct MetricsInner {
+	/// Number of authorities a dispute needed to be sent to, not counting retries.
+	sends_initiated: prometheus::Counter<prometheus::U64>,
+	/// Number of retries of previously failed sends.
+	sends_retried: prometheus::Counter<prometheus::U64>,
+	/// Number of sends that got confirmed by the recipient.
+	sends_succeeded: prometheus::Counter<prometheus::U64>,
+	/// Number of sends that failed and are going to be retried.
+	sends_failed: prometheus::Counter<prometheus::U64>,
+	/// Number of requests currently in flight or queued, across all disputes.
+	sends_pending: prometheus::Gauge<prometheus::U64>,
+}
+
+impl Metrics {
+	/// Record a request having been dispatched to an authority.
+	///
+	/// `is_retry` should be `true` if this is a retry of a previously failed send, `false` if it
+	/// is the first attempt at reaching this authority for the dispute.
+	pub fn on_sent_request(&self, is_retry: bool) {
+		if let Some(metrics) = &self.0 {
+			if is_retry {
+				metrics.sends_retried.inc();
+			} else {
+				metrics.sends_initiated.inc();
+			}
+		}
+	}
+
+	/// Record a successful delivery.
+	pub fn on_sent_request_succeeded(&self) {
+		if let Some(metrics) = &self.0 {
+			metrics.sends_succeeded.inc();
+		}
+	}
+
+	/// Record a failed delivery, to be retried later.
+	pub fn on_sent_request_failed(&self) {
+		if let Some(metrics) = &self.0 {
+			metrics.sends_failed.inc();
+		}
+	}
+
+	/// Note that `count` more deliveries have entered the `Pending`/`Waiting` state.
+	///
+	/// `sends_pending` is a single gauge shared by every `SendTask`'s `Metrics` clone, so it must
+	/// only ever be adjusted by the net change at a transition, never overwritten with one
+	/// `SendTask`'s own count - that would clobber every other dispute's contribution.
+	pub fn note_pending_started(&self, count: usize) {
+		if let Some(metrics) = &self.0 {
+			metrics.sends_pending.add(count as u64);
+		}
+	}
+
+	/// Note that `count` deliveries have left the `Pending`/`Waiting` state - whether they
+	/// succeeded, failed, or became irrelevant outright (e.g. evicted on a session change without
+	/// ever reporting back a result).
+	pub fn note_pending_stopped(&self, count: usize) {
+		if let Some(metrics) = &self.0 {
+			metrics.sends_pending.sub(count as u64);
+		}
+	}
+}
+
+impl metrics::Metrics for Metrics {
+	fn try_register(
+		registry: &prometheus::Registry,
+	) -> std::result::Result<Self, prometheus::PrometheusError> {
+		let metrics = MetricsInner {
+			sends_initiated: prometheus::register(
+				prometheus::Counter::new(
+					"polkadot_parachain_dispute_sends_initiated_total",
+					"Number of authorities a dispute needed to be sent to, not counting retries.",
+				)?,
+				registry,
+			)?,
+			sends_retried: prometheus::register(
+				prometheus::Counter::new(
+					"polkadot_parachain_dispute_sends_retried_total",
+					"Number of retries of previously failed dispute sends.",
+				)?,
+				registry,
+			)?,
+			sends_succeeded: prometheus::register(
+				prometheus::Counter::new(
+					"polkadot_parachain_dispute_sends_succeeded_total",
+					"Number of dispute sends that got confirmed by the recipient.",
+				)?,
+				registry,
+			)?,
+			sends_failed: prometheus::register(
+				prometheus::Counter::new(
+					"polkadot_parachain_dispute_sends_failed_total",
+					"Number of dispute sends that failed and are going to be retried.",
+				)?,
+				registry,
+			)?,
+			sends_pending: prometheus::register(
+				prometheus::Gauge::new(
+					"polkadot_parachain_dispute_sends_pending",
+					"Number of dispute sending requests currently in flight or queued.",
+				)?,
+				registry,
+			)?,
+		};
+		Ok(Metrics(Some(metrics)))
+	}
+}
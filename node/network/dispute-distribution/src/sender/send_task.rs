@@ -16,13 +16,17 @@
 
 
 use std::collections::HashMap;
-use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use futures::Future;
 use futures::FutureExt;
 use futures::SinkExt;
 use futures::channel::mpsc;
 use futures::future::RemoteHandle;
+use rand::Rng;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 
 use polkadot_node_network_protocol::{
 	IfDisconnected,
@@ -42,8 +46,53 @@ use polkadot_subsystem::{
 
 use super::error::{Fatal, Result};
 
+use crate::metrics::Metrics;
 use crate::LOG_TARGET;
 
+/// Base delay before the first retry of a failed send.
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(2);
+
+/// Upper bound on the retry delay, before jitter is added.
+///
+/// Without a cap, a validator that has been unreachable for a long time would otherwise end up
+/// with retry intervals of several minutes, which is no longer useful once a dispute is
+/// time-critical.
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Upper bound on the random jitter added on top of the computed backoff.
+///
+/// Jitter avoids many `SendTask`s ending up retrying the very same unreachable authority in
+/// lock-step.
+const RETRY_JITTER: Duration = Duration::from_millis(500);
+
+/// How soon to re-check a stalled queue for a free `sends_semaphore` permit.
+///
+/// Queued authorities are not failing - they are just waiting for capacity that other disputes
+/// are using. Nothing of ours necessarily ever completes to wake us up (e.g. if this whole
+/// `SendTask` got zero permits at dispatch time), so `next_retry_at` needs to hand back a
+/// near-term wakeup whenever the queue is non-empty, instead of relying solely on backoff timers.
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Default number of dispute sending requests allowed to be in flight at once.
+///
+/// This limit is shared via the `Arc<Semaphore>` passed into `SendTask::new`, so it actually
+/// bounds the total number of in-flight dispute sending requests across all active disputes, not
+/// just a single one.
+pub const MAX_PARALLEL_SENDS: usize = 50;
+
+/// Priority tier of a recipient authority.
+///
+/// Used to order dispatch so the most consensus-critical recipients are contacted first whenever
+/// `sends_semaphore` does not have enough permits for everyone at once.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+enum Priority {
+	/// Parachain validators of the disputed candidate's session - the ones who actually
+	/// backed/checked the candidate and are therefore the most important to reach.
+	Validator,
+	/// Any other authority of a currently active session.
+	Authority,
+}
+
 /// Delivery status for a particular dispute.
 ///
 /// Keeps track of all the validators that have to be reached for a dispute.
@@ -62,14 +111,50 @@ pub struct SendTask {
 
 	/// Sender to be cloned for tasks.
 	tx: mpsc::Sender<FromSendingTask>,
+
+	/// Limits the number of requests that may be in flight at once, shared with all other
+	/// `SendTask`s so the node as a whole does not open unbounded numbers of outgoing requests.
+	sends_semaphore: Arc<Semaphore>,
+
+	/// Authorities waiting for a permit to become available, ordered by `Priority` and then by
+	/// the order they were queued in.
+	queued: VecDeque<(AuthorityDiscoveryId, u32, Priority)>,
+
+	/// Prometheus metrics for observing dispute propagation.
+	metrics: Metrics,
 }
 
 /// Status of a particular vote/statement delivery to a particular validator.
 enum DeliveryStatus {
+	/// Authority is queued up, waiting for a free slot in `sends_semaphore`.
+	Waiting,
 	/// Request is still in flight.
-	Pending(RemoteHandle<()>),
+	///
+	/// The `u32` is the number of attempts made so far, including this one - needed so we know
+	/// how much to back off, should this attempt fail as well.
+	Pending(RemoteHandle<()>, u32),
 	/// Succeeded - no need to send request to this peer anymore.
 	Succeeded,
+	/// Send failed and is scheduled to be retried once `next_attempt` has elapsed.
+	Failed {
+		/// When `refresh_sends` should try getting this request out again.
+		next_attempt: Instant,
+		/// Number of attempts made so far (the initial one counts as the first attempt).
+		attempts: u32,
+	},
+}
+
+/// Compute the point in time at which the next retry for a failing send should happen.
+///
+/// Uses exponential backoff based on the number of `attempts` made so far, capped at
+/// `RETRY_MAX_DELAY` and with some jitter added on top, so we don't end up hot-looping on
+/// unreachable peers nor retrying them all in lock-step.
+fn next_retry_time(attempts: u32) -> Instant {
+	let backoff = RETRY_BASE_DELAY
+		.saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+		.min(RETRY_MAX_DELAY);
+	let jitter = Duration::from_millis(rand::thread_rng().gen_range(0..RETRY_JITTER.as_millis() as u64));
+	Instant::now() + backoff + jitter
 }
 
 /// Messages from tasks trying to get disputes delievered.
@@ -97,6 +182,8 @@ impl SendTask
 		runtime: &mut RuntimeInfo,
 		active_sessions: &HashMap<SessionIndex,Hash>,
 		tx: mpsc::Sender<FromSendingTask>,
+		sends_semaphore: Arc<Semaphore>,
+		metrics: Metrics,
 		request: DisputeRequest,
 	) -> Result<Self> {
 		let mut send_task = Self {
@@ -104,6 +191,9 @@ impl SendTask
 			deliveries: HashMap::new(),
 			has_failed_sends: false,
 			tx,
+			sends_semaphore,
+			queued: VecDeque::new(),
+			metrics,
 		};
 		send_task.refresh_sends(
 			ctx,
@@ -125,35 +215,159 @@ impl SendTask
 	) -> Result<()> {
 		let new_authorities = self.get_relevant_validators(ctx, runtime, active_sessions).await?;
 
-		let add_authorities = new_authorities
+		// Start a send for any authority that is either brand new or whose last attempt failed
+		// and is due for a retry. Authorities that failed but are not due yet, or that are
+		// already queued up waiting for a permit, are left alone.
+		let now = Instant::now();
+		let mut add_authorities: Vec<_> = new_authorities
 			.iter()
-			.filter(|a| !self.deliveries.contains_key(a))
-			.map(Clone::clone)
+			.filter_map(|(a, priority)| match self.deliveries.get(a) {
+				None => Some((a.clone(), 0, *priority)),
+				Some(DeliveryStatus::Failed { next_attempt, attempts }) if *next_attempt <= now =>
+					Some((a.clone(), *attempts, *priority)),
+				Some(DeliveryStatus::Failed { .. })
+				| Some(DeliveryStatus::Waiting)
+				| Some(DeliveryStatus::Pending(_, _))
+				| Some(DeliveryStatus::Succeeded) => None,
+			})
 			.collect();
+		// Parachain validators of the disputed session go out first:
+		add_authorities.sort_by_key(|(_, _, priority)| *priority);
+
+		// Get rid of dead/irrelevant tasks/statuses. Anything still `Pending`/`Waiting` here has
+		// its `RemoteHandle` dropped (for `Pending`) or is simply forgotten about (for `Waiting`)
+		// without ever reporting a result back to us, so account for it leaving the gauge now -
+		// nothing else is ever going to:
+		let evicted_pending = self
+			.deliveries
+			.iter()
+			.filter(|(a, status)| {
+				!new_authorities.contains_key(a) &&
+					matches!(status, DeliveryStatus::Pending(_, _) | DeliveryStatus::Waiting)
+			})
+			.count();
+		self.deliveries.retain(|k, _| new_authorities.contains_key(k));
+		self.queued.retain(|(a, _, _)| new_authorities.contains_key(a));
+		if evicted_pending > 0 {
+			self.metrics.note_pending_stopped(evicted_pending);
+		}
 
-		// Get rid of dead/irrelevant tasks/statuses:
-		self.deliveries.retain(|k, _| new_authorities.contains(k));
+		// Start any new tasks that are needed, bounded by `sends_semaphore`. New authorities join
+		// the queue in `Priority` order, behind already queued up authorities of the same tier, and
+		// the whole queue gets another chance: capacity in `sends_semaphore` might have freed up
+		// since we last tried, due to other disputes finishing their sends. Without this, a
+		// `SendTask` that got zero permits at dispatch time would have no `Pending` send of its own
+		// to trigger a retry via `on_finished_send`, and its queue would never drain.
+		for (authority, attempts, priority) in add_authorities {
+			self.queue_authority(authority, attempts, priority);
+		}
+		self.try_dispatch_queued(ctx).await?;
+		self.has_failed_sends = false;
+		Ok(())
+	}
 
-		// Start any new tasks that are needed:
+	/// Try to get the given authorities' requests out, subject to `sends_semaphore`.
+	///
+	/// `authorities` is expected to already be ordered by `Priority`. Authorities for which no
+	/// permit is currently available are queued up, preserving that order, and get another chance
+	/// whenever one of this task's own requests finishes or `refresh_sends` runs, see
+	/// `try_dispatch_queued`.
+	async fn dispatch<Context: SubsystemContext>(
+		&mut self,
+		ctx: &mut Context,
+		authorities: Vec<(AuthorityDiscoveryId, u32, Priority)>,
+	) -> Result<()> {
+		let mut to_send = Vec::with_capacity(authorities.len());
+		// Authorities already `Waiting` (being requeued by `try_dispatch_queued`) are already
+		// accounted for in `sends_pending` - only count those entering `Pending`/`Waiting` for the
+		// first time, whichever branch below they end up taking:
+		let mut newly_pending = 0usize;
+		for (authority, attempts, priority) in authorities {
+			if !matches!(
+				self.deliveries.get(&authority),
+				Some(DeliveryStatus::Pending(_, _)) | Some(DeliveryStatus::Waiting)
+			) {
+				newly_pending += 1;
+			}
+			match Arc::clone(&self.sends_semaphore).try_acquire_owned() {
+				Ok(permit) => to_send.push((authority, attempts, permit)),
+				Err(_) => {
+					self.deliveries.insert(authority.clone(), DeliveryStatus::Waiting);
+					self.queue_authority(authority, attempts, priority);
+				}
+			}
+		}
 		let new_statuses = send_requests(
 			ctx,
 			self.tx.clone(),
-			add_authorities,
+			to_send,
 			self.request.clone(),
+			&self.metrics,
 		).await?;
 
 		self.deliveries.extend(new_statuses.into_iter());
-		self.has_failed_sends = false;
+		if newly_pending > 0 {
+			self.metrics.note_pending_started(newly_pending);
+		}
 		Ok(())
 	}
 
+	/// Insert an authority into `queued`, keeping the queue ordered by `Priority`.
+	fn queue_authority(&mut self, authority: AuthorityDiscoveryId, attempts: u32, priority: Priority) {
+		let pos = self.queued.iter().position(|(_, _, p)| *p > priority).unwrap_or(self.queued.len());
+		self.queued.insert(pos, (authority, attempts, priority));
+	}
+
+	/// Give every currently queued authority another shot at acquiring a `sends_semaphore` permit.
+	///
+	/// Called both whenever one of our own sends finishes (freeing our own permit) and from
+	/// `refresh_sends` (capacity elsewhere may have freed up). `dispatch` re-queues, in priority
+	/// order, whatever still doesn't fit.
+	async fn try_dispatch_queued<Context: SubsystemContext>(&mut self, ctx: &mut Context) -> Result<()> {
+		let queued = std::mem::take(&mut self.queued).into_iter().collect();
+		self.dispatch(ctx, queued).await
+	}
+
 	/// Whether or not any sends have failed since the last refreshed.
 	pub fn has_failed_sends(&self) -> bool {
 		self.has_failed_sends
 	}
 
+	/// The earliest point in time at which `refresh_sends` should be called again.
+	///
+	/// Returns `None` if there is currently nothing to retry, in which case the subsystem does
+	/// not need to arm a retry timer for this `SendTask` at all.
+	pub fn next_retry_at(&self) -> Option<Instant> {
+		let next_backoff = self
+			.deliveries
+			.values()
+			.filter_map(|status| match status {
+				DeliveryStatus::Failed { next_attempt, .. } => Some(*next_attempt),
+				DeliveryStatus::Waiting | DeliveryStatus::Pending(_, _) | DeliveryStatus::Succeeded =>
+					None,
+			})
+			.min();
+
+		if self.queued.is_empty() {
+			return next_backoff
+		}
+		// Queued authorities are not on a backoff schedule - they are waiting on
+		// `sends_semaphore`, which can free up independently of anything failing here. Make sure
+		// `refresh_sends` gets called again soon regardless, so the queue cannot stall forever:
+		let queue_poll = Instant::now() + QUEUE_POLL_INTERVAL;
+		Some(next_backoff.map_or(queue_poll, |t| t.min(queue_poll)))
+	}
+
 	/// Handle a finished response waiting task.
-	pub fn on_finished_send(&mut self, authority: &AuthorityDiscoveryId, result: TaskResult) {
+	///
+	/// The task that just finished was holding a permit on `sends_semaphore`, so on top of
+	/// recording the result, this gives the next queued authority, if any, a chance to be sent.
+	pub async fn on_finished_send<Context: SubsystemContext>(
+		&mut self,
+		ctx: &mut Context,
+		authority: &AuthorityDiscoveryId,
+		result: TaskResult,
+	) -> Result<()> {
 		match result {
 			TaskResult::Failed => {
 				tracing::warn!(
@@ -163,14 +377,31 @@ impl SendTask
 					"Could not get our message out! If this keeps happening, then check chain whether the dispute made it there."
 				);
 				self.has_failed_sends = true;
-				// Remove state, so we know what to try again:
-				self.deliveries.remove(authority);
+				self.metrics.on_sent_request_failed();
+				let was_pending = matches!(
+					self.deliveries.get(authority),
+					Some(DeliveryStatus::Pending(_, _)) | Some(DeliveryStatus::Waiting)
+				);
+				let attempts = match self.deliveries.get(authority) {
+					Some(DeliveryStatus::Pending(_, attempts)) => *attempts,
+					_ => 1,
+				};
+				// Schedule a retry with backoff, instead of immediately hot-looping on an
+				// unreachable authority:
+				self.deliveries.insert(
+					authority.clone(),
+					DeliveryStatus::Failed { next_attempt: next_retry_time(attempts), attempts },
+				);
+				if was_pending {
+					self.metrics.note_pending_stopped(1);
+				}
 			}
 			TaskResult::Succeeded => {
-				let status = match self.deliveries.get_mut(&authority) {
+				match self.deliveries.get_mut(authority) {
 					None => {
 						// Can happen when a sending became irrelevant while the response was already
-						// queued.
+						// queued. `refresh_sends` already accounted for it leaving `sends_pending`
+						// when it evicted the delivery, so there is nothing to do here.
 						tracing::debug!(
 							target: LOG_TARGET,
 							candidate = ?self.request.0.candidate_receipt.hash(),
@@ -178,27 +409,36 @@ impl SendTask
 							?result,
 							"Received `FromSendingTask::Finished` for non existing task."
 						);
-						return
 					}
-					Some(status) => status,
-				};
-				// We are done here:
-				*status = DeliveryStatus::Succeeded;
+					Some(status) => {
+						// We are done here:
+						*status = DeliveryStatus::Succeeded;
+						self.metrics.on_sent_request_succeeded();
+						self.metrics.note_pending_stopped(1);
+					}
+				}
 			}
 		}
+		self.try_dispatch_queued(ctx).await?;
+		Ok(())
 	}
 
 
-	/// Determine all validators that should receive the given dispute requests.
+	/// Determine all validators that should receive the given dispute requests, tagged with their
+	/// `Priority`.
 	///
 	/// This is all parachain validators of the session the candidate occurred and all authorities
-	/// of all currently active sessions, determined by currently active heads.
+	/// of all currently active sessions, determined by currently active heads. Parachain
+	/// validators of the disputed session are tagged `Priority::Validator`, as they actually
+	/// backed/checked the candidate and are therefore the most important to reach; everyone else
+	/// is tagged `Priority::Authority`. An authority present in both groups keeps the higher
+	/// `Priority::Validator` tier.
 	async fn get_relevant_validators<Context: SubsystemContext>(
 		&self,
 		ctx: &mut Context,
 		runtime: &mut RuntimeInfo,
 		active_sessions: &HashMap<SessionIndex, Hash>,
-	) -> Result<HashSet<AuthorityDiscoveryId>> {
+	) -> Result<HashMap<AuthorityDiscoveryId, Priority>> {
 		let ref_head = self.request.0.candidate_receipt.descriptor.relay_parent;
 		// Parachain validators:
 		let info = runtime
@@ -206,13 +446,13 @@ impl SendTask
 			.await?;
 		let session_info = &info.session_info;
 		let validator_count = session_info.validators.len();
-		let mut authorities: HashSet<_> = session_info
+		let mut authorities: HashMap<_, _> = session_info
 			.discovery_keys
 			.iter()
 			.take(validator_count)
 			.enumerate()
 			.filter(|(i, _)| Some(ValidatorIndex(*i as _)) != info.validator_info.our_index)
-			.map(|(_, v)| v.clone())
+			.map(|(_, v)| (v.clone(), Priority::Validator))
 			.collect();
 
 		// Current authorities:
@@ -225,7 +465,11 @@ impl SendTask
 				.enumerate()
 				.filter(|(i, _)| Some(ValidatorIndex(*i as _)) != info.validator_info.our_index)
 				.map(|(_, v)| v.clone());
-			authorities.extend(new_set);
+			for authority in new_set {
+				// Don't downgrade an authority that is also a parachain validator of the
+				// disputed session:
+				authorities.entry(authority).or_insert(Priority::Authority);
+			}
 		}
 		Ok(authorities)
 	}
@@ -238,13 +482,21 @@ impl SendTask
 async fn send_requests<Context: SubsystemContext>(
 	ctx: &mut Context,
 	tx: mpsc::Sender<FromSendingTask>,
-	receivers: Vec<AuthorityDiscoveryId>,
+	receivers: Vec<(AuthorityDiscoveryId, u32, OwnedSemaphorePermit)>,
 	req: DisputeRequest,
+	metrics: &Metrics,
 ) -> Result<HashMap<AuthorityDiscoveryId, DeliveryStatus>> {
+	if receivers.is_empty() {
+		// Nothing got a permit this round (e.g. everything ended up queued) - don't bother the
+		// network bridge with an empty `SendRequests`.
+		return Ok(HashMap::new())
+	}
+
 	let mut statuses = HashMap::with_capacity(receivers.len());
 	let mut reqs = Vec::with_capacity(receivers.len());
 
-	for receiver in receivers {
+	for (receiver, prior_attempts, permit) in receivers {
+		metrics.on_sent_request(prior_attempts > 0);
 		let (outgoing, pending_response) = OutgoingRequest::new(
 			Recipient::Authority(receiver.clone()),
 			req.clone(),
@@ -257,12 +509,13 @@ async fn send_requests<Context: SubsystemContext>(
 			req.0.candidate_receipt.hash(),
 			receiver.clone(),
 			tx.clone(),
+			permit,
 		);
 
 		let (remote, remote_handle) = fut.remote_handle();
 		ctx.spawn("dispute-sender", remote.boxed())
 			.map_err(Fatal::SpawnTask)?;
-		statuses.insert(receiver, DeliveryStatus::Pending(remote_handle));
+		statuses.insert(receiver, DeliveryStatus::Pending(remote_handle, prior_attempts + 1));
 	}
 
 	let msg = NetworkBridgeMessage::SendRequests(
@@ -275,13 +528,19 @@ async fn send_requests<Context: SubsystemContext>(
 }
 
 /// Future to be spawned in a task for awaiting a response.
+///
+/// Holds on to `permit` for as long as the request is in flight, releasing our slot in
+/// `sends_semaphore` before notifying the subsystem of the result - so a queued up request
+/// elsewhere can immediately take our place instead of racing our still-held permit.
 async fn wait_response_task(
 	pending_response: impl Future<Output = OutgoingResult<DisputeResponse>>,
 	candidate_hash: CandidateHash,
 	receiver: AuthorityDiscoveryId,
 	mut tx: mpsc::Sender<FromSendingTask>,
+	permit: OwnedSemaphorePermit,
 ) {
 	let result = pending_response.await;
+	drop(permit);
 	let msg = match result {
 		Err(err) => {
 			tracing::warn!(
@@ -311,3 +570,57 @@ async fn wait_response_task(
 		);
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn priority_orders_validators_before_authorities() {
+		assert!(Priority::Validator < Priority::Authority);
+
+		let mut priorities = vec![Priority::Authority, Priority::Validator, Priority::Authority];
+		priorities.sort();
+		assert_eq!(priorities, vec![Priority::Validator, Priority::Authority, Priority::Authority]);
+	}
+
+	#[test]
+	fn next_retry_time_backs_off_exponentially_and_caps() {
+		let now = Instant::now();
+
+		// First attempt should be roughly `RETRY_BASE_DELAY` out, plus up to `RETRY_JITTER`:
+		let first = next_retry_time(1);
+		assert!(first >= now + RETRY_BASE_DELAY);
+		assert!(first <= now + RETRY_BASE_DELAY + RETRY_JITTER);
+
+		// Each further attempt should at least double the previous backoff, until the cap kicks in:
+		let second = next_retry_time(2);
+		assert!(second >= now + RETRY_BASE_DELAY * 2);
+
+		// No matter how many attempts, we should never exceed `RETRY_MAX_DELAY` + jitter:
+		let many = next_retry_time(20);
+		assert!(many <= now + RETRY_MAX_DELAY + RETRY_JITTER);
+	}
+
+	#[test]
+	fn next_retry_time_adds_jitter() {
+		// With enough samples for the same `attempts`, we should see more than one distinct
+		// value - otherwise jitter is not actually being applied.
+		// Compare whole-millisecond offsets from the time of each call, so the clock advancing
+		// between calls does not by itself make the samples differ.
+		let samples: std::collections::HashSet<_> = (0..20)
+			.map(|_| {
+				let before = Instant::now();
+				(next_retry_time(1) - before).as_millis()
+			})
+			.collect();
+		assert!(samples.len() > 1, "next_retry_time should vary due to jitter");
+	}
+
+	// `SendTask` itself can only be constructed via `SendTask::new`, which requires a full
+	// `SubsystemContext` and `RuntimeInfo` (and a real `DisputeRequest`) to drive `refresh_sends`.
+	// That harness lives in `polkadot-node-subsystem-test-helpers`, which this checkout does not
+	// vendor, so the tests above cover only pure logic: `Priority`'s ordering and
+	// `next_retry_time`'s backoff and jitter. The queue handling of `SendTask`, including
+	// `next_retry_at`, is not tested here.
+}